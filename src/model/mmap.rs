@@ -0,0 +1,309 @@
+//! A memory-mapped, lazily-deserialized alternative to the bincode format used by
+//! `Model::save`/`Model::load`.
+//!
+//! `Model::load` deserializes every tree's weight matrices up front, which is wasteful for
+//! forests with millions of labels when a single query only ever visits a small fraction of
+//! nodes. The format here instead stores a small header (`n_features`, `hyper_parm`, and a
+//! byte-offset table mirroring the tree topology) followed by each node's weight matrix as its
+//! own addressable region, so `MmapModel::predict` can fault in just the matrices that beam
+//! search actually visits.
+
+use super::{BeamWidth, Model, TrainHyperParam, Tree, TreeNode};
+use crate::{DenseVec, Index, IndexValueVec, SparseMat};
+use hashbrown::HashMap;
+use itertools::Itertools;
+use memmap::Mmap;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io;
+use std::mem::swap;
+use std::path::Path;
+
+/// Byte range of a node's serialized weight matrix within the mmap's matrix region.
+type MatrixOffset = (u64, u64);
+
+/// Mirrors `TreeNode`'s topology, but holds matrix offsets instead of the matrices themselves.
+#[derive(Debug, Serialize, Deserialize)]
+enum NodeIndex {
+    BranchNode {
+        matrix_offset: MatrixOffset,
+        children: Vec<NodeIndex>,
+    },
+    LeafNode {
+        matrix_offset: MatrixOffset,
+        labels: Vec<Index>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    n_features: usize,
+    hyper_parm: TrainHyperParam,
+    trees: Vec<NodeIndex>,
+}
+
+/// Serializes `model` in the mmap-friendly format: an 8-byte little-endian header length,
+/// the bincode-serialized `Header`, and finally the concatenated weight matrix bytes that the
+/// header's offsets point into.
+pub fn save<W: io::Write>(model: &Model, mut writer: W) -> io::Result<()> {
+    let mut matrix_bytes = Vec::new();
+    let trees = model
+        .trees
+        .iter()
+        .map(|tree| index_node(&tree.root, &mut matrix_bytes))
+        .collect::<io::Result<Vec<_>>>()?;
+    let header = Header {
+        n_features: model.n_features,
+        hyper_parm: model.hyper_parm.clone(),
+        trees,
+    };
+
+    let header_bytes =
+        bincode::serialize(&header).or_else(|e| Err(io::Error::new(io::ErrorKind::Other, e)))?;
+    writer.write_all(&(header_bytes.len() as u64).to_le_bytes())?;
+    writer.write_all(&header_bytes)?;
+    writer.write_all(&matrix_bytes)?;
+    Ok(())
+}
+
+/// Appends `node`'s weight matrix to `matrix_bytes` and recurses into its children, returning
+/// the offset-only mirror of this subtree.
+fn index_node(node: &TreeNode, matrix_bytes: &mut Vec<u8>) -> io::Result<NodeIndex> {
+    match node {
+        TreeNode::BranchNode {
+            weight_matrix,
+            children,
+        } => {
+            let matrix_offset = append_matrix(weight_matrix, matrix_bytes)?;
+            let children = children
+                .iter()
+                .map(|child| index_node(child, matrix_bytes))
+                .collect::<io::Result<Vec<_>>>()?;
+            Ok(NodeIndex::BranchNode {
+                matrix_offset,
+                children,
+            })
+        }
+        TreeNode::LeafNode {
+            weight_matrix,
+            labels,
+        } => {
+            let matrix_offset = append_matrix(weight_matrix, matrix_bytes)?;
+            Ok(NodeIndex::LeafNode {
+                matrix_offset,
+                labels: labels.clone(),
+            })
+        }
+    }
+}
+
+fn append_matrix(weight_matrix: &SparseMat, matrix_bytes: &mut Vec<u8>) -> io::Result<MatrixOffset> {
+    let start = matrix_bytes.len() as u64;
+    bincode::serialize_into(&mut *matrix_bytes, weight_matrix)
+        .or_else(|e| Err(io::Error::new(io::ErrorKind::Other, e)))?;
+    Ok((start, matrix_bytes.len() as u64 - start))
+}
+
+/// A model opened with `Model::load_mmap`: the file stays memory-mapped, and each node's
+/// weight matrix is deserialized on demand during `predict` instead of all at once.
+pub struct MmapModel {
+    mmap: Mmap,
+    matrix_region_start: u64,
+    n_features: usize,
+    hyper_parm: TrainHyperParam,
+    trees: Vec<NodeIndex>,
+}
+
+impl MmapModel {
+    /// Memory-maps a model file previously written with `Model::save_mmap` and reads its
+    /// header, without touching any weight matrix.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let mut header_len_bytes = [0u8; 8];
+        header_len_bytes.copy_from_slice(&mmap[..8]);
+        let header_len = u64::from_le_bytes(header_len_bytes) as usize;
+        let header: Header = bincode::deserialize(&mmap[8..8 + header_len])
+            .or_else(|e| Err(io::Error::new(io::ErrorKind::Other, e)))?;
+
+        Ok(Self {
+            mmap,
+            matrix_region_start: 8 + header_len as u64,
+            n_features: header.n_features,
+            hyper_parm: header.hyper_parm,
+            trees: header.trees,
+        })
+    }
+
+    /// Deserializes the weight matrix at `offset`, reading straight from the mapped file.
+    fn matrix_at(&self, offset: MatrixOffset) -> io::Result<SparseMat> {
+        let (start, len) = offset;
+        let start = (self.matrix_region_start + start) as usize;
+        let end = start + len as usize;
+        bincode::deserialize(&self.mmap[start..end])
+            .or_else(|e| Err(io::Error::new(io::ErrorKind::Other, e)))
+    }
+
+    /// Normalize and densify the sparse feature vector, mirroring `Model::prepare_dense_feature_vec`.
+    fn prepare_dense_feature_vec(&self, sparse_vec: &[(Index, f32)]) -> DenseVec {
+        let mut dense_vec = DenseVec::zeros(self.n_features + 1);
+        let norm = sparse_vec.iter().map(|(_, v)| v.powi(2)).sum::<f32>().sqrt();
+        for &(index, value) in sparse_vec {
+            dense_vec[index as usize] = value / norm; // l2-normalized
+        }
+        dense_vec[self.n_features] = 1.; // bias
+        dense_vec
+    }
+
+    /// Returns a ranked list of predictions for the given input example, the same way
+    /// `Model::predict` does, except each node's weight matrix is faulted in from the mmap
+    /// only when the beam actually visits it.
+    pub fn predict(&self, feature_vec: &[(Index, f32)], beam_width: BeamWidth) -> io::Result<IndexValueVec> {
+        let feature_vec = self.prepare_dense_feature_vec(feature_vec);
+        let mut label_to_total_score = HashMap::<Index, f32>::new();
+        for tree in &self.trees {
+            for (label, score) in self.predict_tree(tree, feature_vec.view(), beam_width)? {
+                *label_to_total_score.entry(label).or_insert(0.) += score;
+            }
+        }
+
+        let mut label_score_pairs = label_to_total_score
+            .iter()
+            .map(|(&label, &total_score)| (label, total_score / self.trees.len() as f32))
+            .collect_vec();
+        label_score_pairs
+            .sort_unstable_by(|(_, score1), (_, score2)| score2.partial_cmp(score1).unwrap());
+        Ok(label_score_pairs)
+    }
+
+    fn predict_tree(
+        &self,
+        root: &NodeIndex,
+        feature_vec: crate::DenseVecView,
+        beam_width: BeamWidth,
+    ) -> io::Result<IndexValueVec> {
+        let mut curr_level = vec![(root, 0.)];
+        let mut next_level = Vec::new();
+
+        loop {
+            assert!(!curr_level.is_empty());
+
+            curr_level
+                .sort_unstable_by(|(_, score1), (_, score2)| score2.partial_cmp(score1).unwrap());
+            let scores = curr_level.iter().map(|&(_, score)| score).collect_vec();
+            curr_level.truncate(beam_width.keep_count(&scores));
+
+            if let NodeIndex::LeafNode { .. } = curr_level.first().unwrap().0 {
+                break;
+            }
+
+            next_level.clear();
+            for &(node, node_score) in &curr_level {
+                match node {
+                    NodeIndex::BranchNode {
+                        matrix_offset,
+                        children,
+                    } => {
+                        let weight_matrix = self.matrix_at(*matrix_offset)?;
+                        let mut child_scores = super::liblinear::predict_with_classifier_group(
+                            feature_vec,
+                            weight_matrix.view(),
+                            self.hyper_parm.linear.loss_type,
+                        );
+                        for child_score in &mut child_scores {
+                            *child_score += node_score;
+                        }
+                        next_level.extend(children.iter().zip_eq(child_scores.into_iter()));
+                    }
+                    NodeIndex::LeafNode { .. } => unreachable!("The tree is not a complete binary tree."),
+                }
+            }
+            swap(&mut curr_level, &mut next_level);
+        }
+
+        let mut label_scores = Vec::new();
+        for &(leaf, leaf_score) in &curr_level {
+            if let NodeIndex::LeafNode {
+                matrix_offset,
+                labels,
+            } = leaf
+            {
+                let weight_matrix = self.matrix_at(*matrix_offset)?;
+                let mut scores = super::liblinear::predict_with_classifier_group(
+                    feature_vec,
+                    weight_matrix.view(),
+                    self.hyper_parm.linear.loss_type,
+                );
+                for score in &mut scores {
+                    *score = (*score + leaf_score).exp();
+                }
+                label_scores.extend(labels.iter().cloned().zip_eq(scores.into_iter()));
+            }
+        }
+        Ok(label_scores)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a single-leaf `TreeNode` with the given labels and explicit, asymmetric weight
+    /// rows — as opposed to an all-zero matrix, which would score every label identically and
+    /// let a broken averaging/ordering/offset computation pass unnoticed.
+    fn dense_leaf(labels: Vec<Index>, rows: &[&[f32]], n_features: usize) -> TreeNode {
+        let mut weight_matrix = SparseMat::zeros(labels.len(), n_features + 1);
+        for (r, row) in rows.iter().enumerate() {
+            for (c, &value) in row.iter().enumerate() {
+                weight_matrix[[r, c]] = value;
+            }
+        }
+        TreeNode::LeafNode {
+            weight_matrix,
+            labels,
+        }
+    }
+
+    /// A tiny two-tree forest of single-leaf trees, small enough to build by hand and to
+    /// round-trip through the mmap format.
+    fn small_model() -> Model {
+        let n_features = 4;
+        let trees = vec![
+            Tree {
+                root: dense_leaf(
+                    vec![0, 1],
+                    &[&[1.0, 0.0, 0.0, 0.0, 0.2], &[0.0, 2.0, 0.0, 0.0, -1.0]],
+                    n_features,
+                ),
+            },
+            Tree {
+                root: dense_leaf(
+                    vec![1, 2],
+                    &[&[0.0, 1.0, 0.0, 0.0, 0.5], &[0.0, 0.0, 3.0, 0.0, -2.0]],
+                    n_features,
+                ),
+            },
+        ];
+        Model::from_trees(trees, n_features, TrainHyperParam::default())
+    }
+
+    #[test]
+    fn save_mmap_then_load_mmap_predict_matches_predict() {
+        let model = small_model();
+
+        let mut bytes = Vec::new();
+        save(&model, &mut bytes).unwrap();
+        let path = std::env::temp_dir().join("omikuji-mmap-save-mmap-then-load-mmap-test.model");
+        std::fs::write(&path, &bytes).unwrap();
+        let mmap_model = MmapModel::open(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        let feature_vec = vec![(0, 1.0), (2, 0.5)];
+        let beam_width = BeamWidth::Absolute(2);
+        assert_eq!(
+            model.predict(&feature_vec, beam_width),
+            mmap_model.predict(&feature_vec, beam_width).unwrap()
+        );
+    }
+}
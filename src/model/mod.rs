@@ -1,19 +1,80 @@
 mod cluster;
 pub mod eval;
 pub mod liblinear;
+pub mod mmap;
 pub mod train;
 
 use crate::{DenseVec, DenseVecView, Index, IndexValueVec, SparseMat};
 use hashbrown::HashMap;
 use itertools::Itertools;
 use log::info;
+use rand::seq::SliceRandom;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::io;
 use std::mem::swap;
 
+/// How many negatives to sample per positive when fitting a classifier for a newly-inserted
+/// label in `Model::add_labels`.
+const NEGATIVE_SAMPLE_RATIO: usize = 5;
+
 /// Model training hyper-parameters.
 pub type TrainHyperParam = train::HyperParam;
 
+/// Controls how many candidate nodes are retained at each level of beam search.
+///
+/// A fixed beam size works well when every query has similar score concentration, but in
+/// practice some inputs are confidently routed down one branch while others are ambiguous
+/// across many. `BeamWidth` lets callers express the desired trade-off between recall and
+/// latency instead of committing to a single global beam size.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum BeamWidth {
+    /// Keep exactly this many of the highest-scoring nodes (the original, fixed behavior).
+    Absolute(usize),
+    /// Keep this fraction of the current level's node count, rounded up to at least 1.
+    Fractional(f32),
+    /// Keep every node whose score is within `margin` of the best score at this level, then
+    /// clamp the retained count into `[min, max]`: truncating the lowest-scoring excess if
+    /// there are more than `max`, or pulling in the next-best nodes if there are fewer than
+    /// `min`. The single best node is always kept, even if `margin` is 0.
+    Adaptive { margin: f32, min: usize, max: usize },
+}
+
+impl BeamWidth {
+    /// Given a level of nodes sorted by descending score, returns how many to retain.
+    fn keep_count(self, sorted_scores: &[f32]) -> usize {
+        assert!(!sorted_scores.is_empty());
+        let keep = match self {
+            BeamWidth::Absolute(n) => n,
+            BeamWidth::Fractional(frac) => {
+                ((sorted_scores.len() as f32 * frac).ceil() as usize).max(1)
+            }
+            BeamWidth::Adaptive { margin, min, max } => {
+                let s_max = sorted_scores[0];
+                let above_margin = sorted_scores
+                    .iter()
+                    .take_while(|&&s| s >= s_max - margin)
+                    .count();
+                above_margin.clamp(min.max(1), max.max(min.max(1)))
+            }
+        };
+        keep.clamp(1, sorted_scores.len())
+    }
+}
+
+/// A leaf discovered while traversing a `Tree`'s structure, as yielded by `Model::leaves`.
+#[derive(Debug, Clone)]
+pub struct LeafInfo<'a> {
+    /// Index of the tree this leaf belongs to, within the model's forest.
+    pub tree: usize,
+    /// Depth of this leaf below its tree's root (the root itself is depth 0).
+    pub depth: usize,
+    /// The path of child indices taken from the tree's root to reach this leaf.
+    pub path: Vec<usize>,
+    /// The labels assigned to this leaf.
+    pub labels: &'a [Index],
+}
+
 /// A Parabel model, which contains a forest of trees.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Model {
@@ -29,8 +90,8 @@ impl Model {
     ///
     /// * `feature_vec` - An input vector for prediction, assumed to be ordered by indices and have
     /// no duplicate or out-of-range indices
-    /// * `beam_size` - Beam size for beam search.
-    pub fn predict(&self, feature_vec: &[(Index, f32)], beam_size: usize) -> IndexValueVec {
+    /// * `beam_width` - Beam width for beam search.
+    pub fn predict(&self, feature_vec: &[(Index, f32)], beam_width: BeamWidth) -> IndexValueVec {
         let feature_vec = self.prepare_dense_feature_vec(feature_vec);
         let mut label_to_total_score = HashMap::<Index, f32>::new();
         let tree_predictions: Vec<_> = self
@@ -39,7 +100,7 @@ impl Model {
             .map(|tree| {
                 tree.predict(
                     feature_vec.view(),
-                    beam_size,
+                    beam_width,
                     self.hyper_parm.linear.loss_type,
                 )
             })
@@ -51,6 +112,59 @@ impl Model {
             }
         }
 
+        self.finalize_scores(label_to_total_score)
+    }
+
+    /// Parallel counterpart of `predict` that scores trees concurrently with rayon, then sums
+    /// the per-tree results into a single `label_to_total_score` map, in tree order, before ranking.
+    /// Produces results bit-for-bit identical to `predict` (same averaging, same final sort);
+    /// only the wall-clock time differs.
+    pub fn predict_parallel(
+        &self,
+        feature_vec: &[(Index, f32)],
+        beam_width: BeamWidth,
+    ) -> IndexValueVec {
+        let feature_vec = self.prepare_dense_feature_vec(feature_vec);
+
+        // Score trees in parallel, but collect into a `Vec` (preserving `self.trees`'s order)
+        // rather than folding in rayon's nondeterministic reduction order: f32 addition isn't
+        // associative, so summing in whatever order threads happen to finish would make the
+        // result depend on scheduling instead of matching `predict` bit-for-bit.
+        let tree_predictions: Vec<_> = self
+            .trees
+            .par_iter()
+            .map(|tree| {
+                tree.predict(feature_vec.view(), beam_width, self.hyper_parm.linear.loss_type)
+            })
+            .collect();
+
+        let mut label_to_total_score = HashMap::<Index, f32>::new();
+        for label_score_pairs in tree_predictions {
+            for (label, score) in label_score_pairs {
+                *label_to_total_score.entry(label).or_insert(0.) += score;
+            }
+        }
+
+        self.finalize_scores(label_to_total_score)
+    }
+
+    /// Scores many examples in parallel with rayon, one task per example. Prefer this over
+    /// calling `predict` (sequential) or `predict_parallel` (parallel across trees) in a loop
+    /// when scoring a batch, since it parallelizes across examples instead.
+    pub fn predict_batch(
+        &self,
+        examples: &[&[(Index, f32)]],
+        beam_width: BeamWidth,
+    ) -> Vec<IndexValueVec> {
+        examples
+            .par_iter()
+            .map(|feature_vec| self.predict(feature_vec, beam_width))
+            .collect()
+    }
+
+    /// Averages accumulated per-label scores over `self.trees.len()` and ranks them
+    /// descending, as the final step shared by `predict` and `predict_parallel`.
+    fn finalize_scores(&self, label_to_total_score: HashMap<Index, f32>) -> IndexValueVec {
         let mut label_score_pairs = label_to_total_score
             .iter()
             .map(|(&label, &total_score)| (label, total_score / self.trees.len() as f32))
@@ -103,6 +217,187 @@ impl Model {
         );
         Ok(model)
     }
+
+    /// Serializes this model into the on-disk layout read by `load_mmap`: a header holding
+    /// `n_features`, `hyper_parm`, and a byte-offset table, followed by every node's weight
+    /// matrix stored as its own independently addressable region. This is an alternative to
+    /// `save`, not a replacement; use it when the model is large enough that `load_mmap`'s
+    /// lazy deserialization is worth the extra file format.
+    pub fn save_mmap<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        mmap::save(self, writer)
+    }
+
+    /// Opens a model previously written with `save_mmap` by memory-mapping the file instead
+    /// of deserializing it into RAM. Each node's weight matrix is only deserialized, from the
+    /// mapped bytes, when beam search actually visits that node, and dropped again right
+    /// after — so resident memory stays proportional to what a query actually touches rather
+    /// than to the size of the whole forest.
+    pub fn load_mmap<P: AsRef<std::path::Path>>(path: P) -> io::Result<mmap::MmapModel> {
+        mmap::MmapModel::open(path)
+    }
+
+    /// Constructs a model directly from an already-trained forest, e.g. when assembling a
+    /// model from trees produced elsewhere (see `merge`).
+    pub(crate) fn from_trees(trees: Vec<Tree>, n_features: usize, hyper_parm: TrainHyperParam) -> Self {
+        Self {
+            trees,
+            n_features,
+            hyper_parm,
+        }
+    }
+
+    /// Merges another model's trees into this one.
+    ///
+    /// Because `predict` scores each tree independently and only averages per-label scores
+    /// across `self.trees` at the end, a forest is naturally a bag of independently trainable
+    /// trees: `N` workers can each `train` a few trees on their own shard, and a coordinator
+    /// can combine the results with `merge` instead of any one worker needing the full forest
+    /// in memory. Beam-search prediction remains correct after merging for the same reason.
+    ///
+    /// Returns an error if `other` has a different feature dimensionality or linear loss type,
+    /// since trees trained under different assumptions can't be scored and averaged together.
+    pub fn merge(&mut self, other: Model) -> io::Result<()> {
+        if self.n_features != other.n_features {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "cannot merge models with different n_features ({} vs {})",
+                    self.n_features, other.n_features
+                ),
+            ));
+        }
+        if self.hyper_parm.linear.loss_type != other.hyper_parm.linear.loss_type {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot merge models trained with different linear loss types",
+            ));
+        }
+
+        let n_features = self.n_features;
+        let hyper_parm = self.hyper_parm.clone();
+        let mut trees = std::mem::take(&mut self.trees);
+        trees.extend(other.trees);
+        *self = Self::from_trees(trees, n_features, hyper_parm);
+        Ok(())
+    }
+
+    /// Incrementally adds new labels to an already-trained model, without retraining the
+    /// forest from scratch.
+    ///
+    /// For each new label, its positive examples are averaged into a centroid feature vector,
+    /// which is beam-searched (greedily, as if with `BeamWidth::Absolute(1)`) down to its
+    /// best-matching leaf in every tree. A one-vs-rest binary classifier is fit once per label
+    /// — against `NEGATIVE_SAMPLE_RATIO` negatives per positive, sampled from `negatives` — and
+    /// the resulting row is appended, identically, to the matching leaf's `weight_matrix` in
+    /// every tree, with the label appended to that leaf's `labels`.
+    ///
+    /// The whole batch is atomic: every leaf path is resolved and every classifier is fit
+    /// before any tree is mutated, so if fitting fails for any label in any tree, the model is
+    /// left entirely unchanged rather than partially updated.
+    ///
+    /// # Arguments
+    ///
+    /// * `new_labels` - Pairs of `(label, positive feature vector)`; a label may appear more
+    /// than once to supply multiple positive examples.
+    /// * `negatives` - A shared pool of negative feature vectors to sample from when fitting
+    /// each label's classifier.
+    pub fn add_labels(
+        &mut self,
+        new_labels: &[(Index, Vec<(Index, f32)>)],
+        negatives: &[Vec<(Index, f32)>],
+    ) -> io::Result<()> {
+        let mut positives_by_label = HashMap::<Index, Vec<&[(Index, f32)]>>::new();
+        for (label, feature_vec) in new_labels {
+            positives_by_label
+                .entry(*label)
+                .or_insert_with(Vec::new)
+                .push(feature_vec.as_slice());
+        }
+
+        // Densify once: it doesn't depend on the label being inserted, so doing it inside the
+        // loop below would redo the same O(negatives) work once per distinct label.
+        let negatives: Vec<_> = negatives
+            .iter()
+            .map(|v| self.prepare_dense_feature_vec(v))
+            .collect();
+
+        // Stage every (tree, label) insert — leaf path and fitted classifier row — before
+        // mutating any tree, so a failure partway through leaves the model untouched.
+        let mut inserts = Vec::new();
+        for (&label, positives) in &positives_by_label {
+            let positives: Vec<_> = positives
+                .iter()
+                .map(|v| self.prepare_dense_feature_vec(v))
+                .collect();
+            let mut centroid = DenseVec::zeros(self.n_features + 1);
+            for positive in &positives {
+                centroid += positive;
+            }
+            centroid /= positives.len() as f32;
+
+            // Fit the classifier once per label: none of its inputs depend on which tree it
+            // ends up being placed in, so refitting it per-tree would be redundant work on the
+            // single most expensive step of this whole operation.
+            let sample_size = (positives.len() * NEGATIVE_SAMPLE_RATIO).min(negatives.len());
+            let sampled_negatives: Vec<_> = negatives
+                .choose_multiple(&mut rand::thread_rng(), sample_size)
+                .cloned()
+                .collect();
+            let new_row =
+                liblinear::train_classifier(&positives, &sampled_negatives, &self.hyper_parm.linear)
+                    .or_else(|e| Err(io::Error::new(io::ErrorKind::Other, e)))?;
+
+            for (tree_idx, tree) in self.trees.iter().enumerate() {
+                let path = tree.best_leaf_path(centroid.view(), self.hyper_parm.linear.loss_type);
+                inserts.push((tree_idx, path, label, new_row.clone()));
+            }
+        }
+
+        for (tree_idx, path, label, new_row) in inserts {
+            self.trees[tree_idx].apply_label_insert(&path, label, new_row);
+        }
+        Ok(())
+    }
+
+    /// Removes labels from an already-trained model in place.
+    ///
+    /// Each label is located by scanning every tree for the leaf whose `labels` contains it;
+    /// the label and the matching row of that leaf's `weight_matrix` are dropped together so
+    /// `weight_matrix.rows() == labels.len()` keeps holding. Labels that aren't found are
+    /// silently ignored, mirroring how `add_labels` treats each label independently.
+    pub fn remove_labels(&mut self, labels_to_remove: &[Index]) {
+        for &label in labels_to_remove {
+            for tree in &mut self.trees {
+                tree.remove_label(label);
+            }
+        }
+    }
+
+    /// Returns a lazy, read-only iterator over every leaf in the forest, together with its
+    /// tree index, depth, and the path from that tree's root. Only the paths are built up as
+    /// it goes; weight matrices are never cloned or touched, making this cheap to use for debugging a
+    /// trained model, coverage analysis, or tooling that visualizes label partitioning.
+    pub fn leaves(&self) -> impl Iterator<Item = LeafInfo> + '_ {
+        self.trees
+            .iter()
+            .enumerate()
+            .flat_map(|(tree, t)| t.leaves(tree))
+    }
+
+    /// Returns, for every label in the forest, the `(tree, path)` locations of the leaves
+    /// that hold it.
+    pub fn label_to_leaves(&self) -> HashMap<Index, Vec<(usize, Vec<usize>)>> {
+        let mut label_to_leaves = HashMap::<Index, Vec<(usize, Vec<usize>)>>::new();
+        for leaf in self.leaves() {
+            for &label in leaf.labels {
+                label_to_leaves
+                    .entry(label)
+                    .or_insert_with(Vec::new)
+                    .push((leaf.tree, leaf.path.clone()));
+            }
+        }
+        label_to_leaves
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -130,29 +425,162 @@ impl TreeNode {
             false
         }
     }
+
+    /// Greedily descends to the leaf that best matches `feature_vec` (equivalent to beam
+    /// search with a beam width of 1), returning the path of child indices taken to reach it.
+    /// Read-only, so it can be used to decide where an insert would land before committing to
+    /// any mutation.
+    fn best_leaf_path(
+        &self,
+        feature_vec: DenseVecView,
+        liblinear_loss_type: liblinear::LossType,
+    ) -> Vec<usize> {
+        match self {
+            TreeNode::LeafNode { .. } => Vec::new(),
+            TreeNode::BranchNode {
+                weight_matrix,
+                children,
+            } => {
+                let child_scores = liblinear::predict_with_classifier_group(
+                    feature_vec,
+                    weight_matrix.view(),
+                    liblinear_loss_type,
+                );
+                let (best_child, _) = child_scores
+                    .into_iter()
+                    .enumerate()
+                    .max_by(|(_, score1), (_, score2)| score1.partial_cmp(score2).unwrap())
+                    .expect("a branch node always has at least one child");
+                let mut path = vec![best_child];
+                path.extend(children[best_child].best_leaf_path(feature_vec, liblinear_loss_type));
+                path
+            }
+        }
+    }
+
+    /// Resolves `path` (as produced by `best_leaf_path`) to a mutable reference to the leaf
+    /// it points at.
+    fn leaf_at_path_mut(&mut self, path: &[usize]) -> &mut TreeNode {
+        match path.split_first() {
+            None => self,
+            Some((&child, rest)) => match self {
+                TreeNode::BranchNode { children, .. } => children[child].leaf_at_path_mut(rest),
+                TreeNode::LeafNode { .. } => unreachable!("path is longer than the tree is deep"),
+            },
+        }
+    }
+}
+
+/// Stack-based DFS over a `Tree`'s nodes, yielding each `LeafNode` it reaches.
+struct Leaves<'a> {
+    tree: usize,
+    stack: Vec<(&'a TreeNode, Vec<usize>)>,
+}
+
+impl<'a> Iterator for Leaves<'a> {
+    type Item = LeafInfo<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, path)) = self.stack.pop() {
+            match node {
+                TreeNode::LeafNode { labels, .. } => {
+                    return Some(LeafInfo {
+                        tree: self.tree,
+                        depth: path.len(),
+                        path,
+                        labels,
+                    });
+                }
+                TreeNode::BranchNode { children, .. } => {
+                    for (i, child) in children.iter().enumerate().rev() {
+                        let mut child_path = path.clone();
+                        child_path.push(i);
+                        self.stack.push((child, child_path));
+                    }
+                }
+            }
+        }
+        None
+    }
 }
 
 impl Tree {
+    /// Lazily iterates over every leaf in this tree, without cloning any weight matrices.
+    fn leaves(&self, tree: usize) -> Leaves {
+        Leaves {
+            tree,
+            stack: vec![(&self.root, Vec::new())],
+        }
+    }
+
+    /// Path of child indices a new label's centroid would route to, without mutating
+    /// anything. Paired with `apply_label_insert` so callers can stage a whole batch of
+    /// inserts across trees and commit them only once every one has succeeded.
+    fn best_leaf_path(
+        &self,
+        centroid: DenseVecView,
+        liblinear_loss_type: liblinear::LossType,
+    ) -> Vec<usize> {
+        self.root.best_leaf_path(centroid, liblinear_loss_type)
+    }
+
+    /// Appends `new_row` and `label` to the leaf at `path`, which must have come from this
+    /// same tree's `best_leaf_path`.
+    fn apply_label_insert(&mut self, path: &[usize], label: Index, new_row: SparseMat) {
+        let leaf = self.root.leaf_at_path_mut(path);
+        if let TreeNode::LeafNode {
+            weight_matrix,
+            labels,
+        } = leaf
+        {
+            weight_matrix.push_row(new_row.view());
+            labels.push(label);
+            assert_eq!(weight_matrix.rows(), labels.len());
+        }
+    }
+
+    /// Removes `label` from whichever leaf holds it, dropping its weight row along with it.
+    /// Does nothing if the label isn't present in this tree.
+    fn remove_label(&mut self, label: Index) {
+        fn visit(node: &mut TreeNode, label: Index) -> bool {
+            match node {
+                TreeNode::LeafNode {
+                    weight_matrix,
+                    labels,
+                } => match labels.iter().position(|&l| l == label) {
+                    Some(pos) => {
+                        labels.remove(pos);
+                        weight_matrix.remove_row(pos);
+                        assert_eq!(weight_matrix.rows(), labels.len());
+                        true
+                    }
+                    None => false,
+                },
+                TreeNode::BranchNode { children, .. } => {
+                    children.iter_mut().any(|child| visit(child, label))
+                }
+            }
+        }
+        visit(&mut self.root, label);
+    }
+
     fn predict(
         &self,
         feature_vec: DenseVecView,
-        beam_size: usize,
+        beam_width: BeamWidth,
         liblinear_loss_type: liblinear::LossType,
     ) -> IndexValueVec {
-        assert!(beam_size > 0);
-        let mut curr_level = Vec::<(&TreeNode, f32)>::with_capacity(beam_size * 2);
-        let mut next_level = Vec::<(&TreeNode, f32)>::with_capacity(beam_size * 2);
+        let mut curr_level = Vec::<(&TreeNode, f32)>::new();
+        let mut next_level = Vec::<(&TreeNode, f32)>::new();
 
         curr_level.push((&self.root, 0.));
         loop {
             assert!(!curr_level.is_empty());
 
-            if curr_level.len() > beam_size {
-                curr_level.sort_unstable_by(|(_, score1), (_, score2)| {
-                    score2.partial_cmp(score1).unwrap()
-                });
-                curr_level.truncate(beam_size);
-            }
+            curr_level
+                .sort_unstable_by(|(_, score1), (_, score2)| score2.partial_cmp(score1).unwrap());
+            let scores = curr_level.iter().map(|&(_, score)| score).collect_vec();
+            curr_level.truncate(beam_width.keep_count(&scores));
 
             // Iterate until we reach the leaves
             if curr_level.first().unwrap().0.is_leaf() {
@@ -211,3 +639,82 @@ impl Tree {
             .collect_vec()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a single-leaf `TreeNode` with the given labels and explicit, asymmetric weight
+    /// rows — as opposed to an all-zero matrix, which would score every label identically and
+    /// let a broken averaging/ordering/offset computation pass unnoticed.
+    fn dense_leaf(labels: Vec<Index>, rows: &[&[f32]], n_features: usize) -> TreeNode {
+        let mut weight_matrix = SparseMat::zeros(labels.len(), n_features + 1);
+        for (r, row) in rows.iter().enumerate() {
+            for (c, &value) in row.iter().enumerate() {
+                weight_matrix[[r, c]] = value;
+            }
+        }
+        TreeNode::LeafNode {
+            weight_matrix,
+            labels,
+        }
+    }
+
+    /// A tiny two-tree forest of single-leaf trees, small enough to build by hand and cheap
+    /// enough to exercise `predict`, `add_labels`, and `remove_labels` directly.
+    fn small_model() -> Model {
+        let n_features = 4;
+        let trees = vec![
+            Tree {
+                root: dense_leaf(
+                    vec![0, 1],
+                    &[&[1.0, 0.0, 0.0, 0.0, 0.2], &[0.0, 2.0, 0.0, 0.0, -1.0]],
+                    n_features,
+                ),
+            },
+            Tree {
+                root: dense_leaf(
+                    vec![1, 2],
+                    &[&[0.0, 1.0, 0.0, 0.0, 0.5], &[0.0, 0.0, 3.0, 0.0, -2.0]],
+                    n_features,
+                ),
+            },
+        ];
+        Model::from_trees(trees, n_features, TrainHyperParam::default())
+    }
+
+    #[test]
+    fn add_labels_then_remove_labels_preserves_row_label_invariant() {
+        let mut model = small_model();
+        let new_label = 3;
+
+        // `apply_label_insert`/`Tree::remove_label` assert internally that
+        // `weight_matrix.rows() == labels.len()` after every mutation, so a successful,
+        // non-panicking call here is itself evidence the invariant held throughout.
+        model
+            .add_labels(&[(new_label, vec![(0, 1.0), (2, 1.0)])], &[vec![(1, 1.0)]])
+            .expect("add_labels should succeed and keep weight_matrix.rows() == labels.len()");
+        assert!(
+            model.leaves().any(|leaf| leaf.labels.contains(&new_label)),
+            "new label should be inserted into a leaf"
+        );
+
+        model.remove_labels(&[new_label]);
+        assert!(
+            !model.leaves().any(|leaf| leaf.labels.contains(&new_label)),
+            "removed label should no longer be present in any leaf"
+        );
+    }
+
+    #[test]
+    fn predict_parallel_matches_predict() {
+        let model = small_model();
+        let feature_vec = vec![(0, 1.0), (2, 0.5)];
+        let beam_width = BeamWidth::Absolute(2);
+
+        assert_eq!(
+            model.predict(&feature_vec, beam_width),
+            model.predict_parallel(&feature_vec, beam_width)
+        );
+    }
+}